@@ -1,17 +1,16 @@
 use itertools::join;
 use quick_xml::events::Event;
 use quick_xml::Reader;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use snafu::{ResultExt, Snafu};
 use std::collections::HashMap;
+use std::io::Read;
 use std::mem;
+use structopt::clap::arg_enum;
 use structopt::StructOpt;
 
 #[derive(Debug, Snafu)]
 enum Error {
-    #[snafu(display("Could not open bible XML file: {}", source))]
-    OpenXML { source: quick_xml::Error },
-
     #[snafu(display("Unable to parse chapter {}", chapter))]
     BadChapter { chapter: String },
 
@@ -23,6 +22,147 @@ enum Error {
 
     #[snafu(display("Output Error: {}", source))]
     IOError { source: std::io::Error },
+
+    #[snafu(display("Error reading EPUB archive: {}", source))]
+    Zip { source: zip::result::ZipError },
+
+    #[snafu(display("Malformed EPUB: {}", message))]
+    EpubStructure { message: String },
+
+    #[snafu(display("Error reading/writing index cache: {}", source))]
+    Cache { source: bincode::Error },
+
+    #[snafu(display(
+        "Book name {:?} isn't safe to use as a file path component",
+        book
+    ))]
+    UnsafeBookName { book: String },
+}
+
+/// A piece of chapter content in document order: either a section heading
+/// or a single verse, already formatted as `"<num>. <text>"`.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+enum ChapterItem {
+    Heading(String),
+    Verse(String),
+}
+
+/// Groups chapters by book (preserving first-seen book order) and sorts each
+/// book's chapters numerically, the ordering mdBook's `SUMMARY.md` expects.
+fn group_chapters_by_book(
+    chapters: Vec<(BookAndChapter, Vec<ChapterItem>)>,
+) -> Vec<(String, Vec<(usize, Vec<ChapterItem>)>)> {
+    let mut order: Vec<String> = Vec::new();
+    let mut by_book: HashMap<String, Vec<(usize, Vec<ChapterItem>)>> = HashMap::new();
+
+    for (bc, items) in chapters {
+        if !by_book.contains_key(&bc.book) {
+            order.push(bc.book.clone());
+        }
+        by_book
+            .entry(bc.book)
+            .or_insert_with(Vec::new)
+            .push((bc.chapter, items));
+    }
+
+    order
+        .into_iter()
+        .map(|book| {
+            let mut chapters = by_book.remove(&book).unwrap();
+            chapters.sort_by_key(|(num, _)| *num);
+            (book, chapters)
+        })
+        .collect()
+}
+
+/// Rejects a book name that isn't safe to use as a single filesystem path
+/// component: empty, `.`/`..`, containing a path separator, or containing a
+/// NUL byte. Book names come straight from third-party source files (OSIS
+/// `osisID`, Zefania `bname`, ...), so without this check a book name like
+/// `../../../../tmp/pwned` could escape the `src/` tree entirely when joined
+/// onto a path.
+fn sanitize_book_name(book: &str) -> Result<&str, Error> {
+    let is_safe = !book.is_empty()
+        && book != "."
+        && book != ".."
+        && !book.contains(std::path::is_separator)
+        && !book.contains('\0');
+
+    if is_safe {
+        Ok(book)
+    } else {
+        Err(Error::UnsafeBookName {
+            book: book.to_string(),
+        })
+    }
+}
+
+/// Writes an mdBook source tree (`src/SUMMARY.md` plus one Markdown file per
+/// chapter) covering the selected passages. Section headings become their
+/// own Markdown heading lines, `heading_depth` levels below the chapter title.
+fn write_mdbook(
+    chapters: Vec<(BookAndChapter, Vec<ChapterItem>)>,
+    heading_depth: usize,
+) -> Result<(), Error> {
+    let books = group_chapters_by_book(chapters);
+
+    let mut summary = String::from("# Summary\n\n");
+
+    for (book, chapters) in &books {
+        let safe_book = sanitize_book_name(book)?;
+        let book_dir = std::path::Path::new("src").join(safe_book);
+        std::fs::create_dir_all(&book_dir).context(IOError {})?;
+
+        summary.push_str(&format!("- [{0}]()\n", book));
+
+        for (chapter, items) in chapters {
+            let chapter_path = book_dir.join(format!("{}.md", chapter));
+
+            let mut content = format!("# {} {}\n\n", book, chapter);
+            for item in items {
+                match item {
+                    ChapterItem::Heading(text) => {
+                        content.push_str(&"#".repeat(heading_depth));
+                        content.push(' ');
+                        content.push_str(text);
+                        content.push_str("\n\n");
+                    }
+                    ChapterItem::Verse(text) => {
+                        content.push_str(text);
+                        content.push_str("\n\n");
+                    }
+                }
+            }
+            std::fs::write(&chapter_path, content).context(IOError {})?;
+
+            summary.push_str(&format!(
+                "  - [{0} {1}](./{2}/{1}.md)\n",
+                book, chapter, safe_book
+            ));
+        }
+    }
+
+    std::fs::write("src/SUMMARY.md", summary).context(IOError {})?;
+
+    Ok(())
+}
+
+arg_enum! {
+    #[derive(Debug)]
+    enum OutputFormat {
+        Roam,
+        Mdbook,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    enum SchemaKind {
+        Esv,
+        Osis,
+        Zefania,
+        Auto,
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -32,6 +172,32 @@ struct Config {
 
     #[structopt(short = "f", long = "file", default_value = "ESV.xml")]
     path: std::path::PathBuf,
+
+    #[structopt(
+        long = "format",
+        possible_values = &OutputFormat::variants(),
+        case_insensitive = true,
+        default_value = "Roam"
+    )]
+    format: OutputFormat,
+
+    /// Roam/Markdown heading depth to use for section headings found in the source.
+    #[structopt(long = "heading-depth", default_value = "2")]
+    heading_depth: usize,
+
+    /// Bible XML schema the source uses. `auto` sniffs the root element.
+    #[structopt(
+        long = "schema",
+        possible_values = &SchemaKind::variants(),
+        case_insensitive = true,
+        default_value = "Auto"
+    )]
+    schema: SchemaKind,
+
+    /// Build (or reuse) a bincode-cached full-document index next to the
+    /// source file, instead of re-parsing the XML on every invocation.
+    #[structopt(long = "cache")]
+    use_cache: bool,
 }
 
 #[derive(Serialize, Debug)]
@@ -40,7 +206,7 @@ struct RoamDocument {
     children: Vec<RoamBlock>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, PartialEq)]
 struct RoamBlock {
     string: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -49,6 +215,46 @@ struct RoamBlock {
     children: Option<Vec<RoamBlock>>,
 }
 
+/// Turns a chapter's flat `ChapterItem` sequence into `RoamBlock`s, nesting
+/// each heading's subsequent verses as its children instead of leaving
+/// everything flat under the chapter block.
+fn nest_under_headings(items: Vec<ChapterItem>, heading_depth: usize) -> Vec<RoamBlock> {
+    let mut blocks = Vec::new();
+    let mut current_heading: Option<RoamBlock> = None;
+
+    for item in items {
+        match item {
+            ChapterItem::Heading(text) => {
+                if let Some(heading) = current_heading.take() {
+                    blocks.push(heading);
+                }
+                current_heading = Some(RoamBlock {
+                    string: text,
+                    heading: Some(heading_depth),
+                    children: Some(Vec::new()),
+                });
+            }
+            ChapterItem::Verse(text) => {
+                let verse_block = RoamBlock {
+                    string: text,
+                    heading: None,
+                    children: None,
+                };
+                match &mut current_heading {
+                    Some(heading) => heading.children.as_mut().unwrap().push(verse_block),
+                    None => blocks.push(verse_block),
+                }
+            }
+        }
+    }
+
+    if let Some(heading) = current_heading.take() {
+        blocks.push(heading);
+    }
+
+    blocks
+}
+
 fn get_name<'a>(e: &'a quick_xml::events::BytesStart) -> String {
     e.attributes()
         .map(|a| a.unwrap())
@@ -57,6 +263,363 @@ fn get_name<'a>(e: &'a quick_xml::events::BytesStart) -> String {
         .unwrap()
 }
 
+/// A semantic event a `Schema` maps a raw `<Start>` tag onto.
+#[derive(Debug, PartialEq)]
+enum SchemaStartEvent {
+    Book(String),
+    Chapter(usize),
+    Verse(String),
+    Heading,
+}
+
+/// A semantic event a `Schema` maps a raw `</End>` tag onto.
+#[derive(Debug, PartialEq)]
+enum SchemaEndEvent {
+    Book,
+    Chapter,
+    Verse,
+    Heading,
+}
+
+/// Maps a Bible XML dialect's tags onto the book/chapter/verse/heading
+/// events the main parse loop drives, so the loop itself stays schema-agnostic.
+trait Schema {
+    fn classify_start(&self, e: &quick_xml::events::BytesStart) -> Option<SchemaStartEvent>;
+    fn classify_end(&self, e: &quick_xml::events::BytesEnd) -> Option<SchemaEndEvent>;
+}
+
+/// The original hardcoded `<b n=..>/<c n=..>/<v n=..>` dialect this tool shipped with.
+struct EsvSchema;
+
+impl Schema for EsvSchema {
+    fn classify_start(&self, e: &quick_xml::events::BytesStart) -> Option<SchemaStartEvent> {
+        match e.name() {
+            b"b" => Some(SchemaStartEvent::Book(get_name(e))),
+            b"c" => Some(SchemaStartEvent::Chapter(get_name(e).parse().unwrap())),
+            b"v" => Some(SchemaStartEvent::Verse(get_name(e))),
+            b"title" => Some(SchemaStartEvent::Heading),
+            _ => None,
+        }
+    }
+
+    fn classify_end(&self, e: &quick_xml::events::BytesEnd) -> Option<SchemaEndEvent> {
+        match e.name() {
+            b"b" => Some(SchemaEndEvent::Book),
+            b"c" => Some(SchemaEndEvent::Chapter),
+            b"v" => Some(SchemaEndEvent::Verse),
+            b"title" => Some(SchemaEndEvent::Heading),
+            _ => None,
+        }
+    }
+}
+
+/// OSIS: `<div type="book" osisID="Gen">`, `<chapter osisID="Gen.1">`,
+/// `<verse osisID="Gen.1.1">`. The book/chapter/verse number is the last
+/// dot-separated segment of `osisID`.
+///
+/// OSIS modules nest plenty of non-book `<div>`s (`type="section"`,
+/// `"majorSection"`, `"lg"`, ...) inside the book div, and a `BytesEnd` has
+/// no attributes to tell those closes apart from the book's own. So this
+/// schema tracks div-nesting depth itself: `classify_start` records the
+/// depth at which the book div opened, and `classify_end` only emits
+/// `SchemaEndEvent::Book` for the `</div>` that closes back to that depth.
+struct OsisSchema {
+    div_depth: std::cell::Cell<usize>,
+    book_div_depth: std::cell::Cell<Option<usize>>,
+}
+
+impl OsisSchema {
+    fn new() -> Self {
+        OsisSchema {
+            div_depth: std::cell::Cell::new(0),
+            book_div_depth: std::cell::Cell::new(None),
+        }
+    }
+}
+
+fn osis_last_segment(id: &str) -> &str {
+    id.rsplit('.').next().unwrap_or(id)
+}
+
+impl Schema for OsisSchema {
+    fn classify_start(&self, e: &quick_xml::events::BytesStart) -> Option<SchemaStartEvent> {
+        match e.name() {
+            b"div" => {
+                let depth = self.div_depth.get() + 1;
+                self.div_depth.set(depth);
+
+                let is_book = attribute_value(e, b"type").as_deref() == Some("book");
+                if !is_book || self.book_div_depth.get().is_some() {
+                    return None;
+                }
+                let book = attribute_value(e, b"osisID").map(SchemaStartEvent::Book);
+                if book.is_some() {
+                    self.book_div_depth.set(Some(depth));
+                }
+                book
+            }
+            b"chapter" => attribute_value(e, b"osisID")
+                .map(|id| osis_last_segment(&id).parse::<usize>())
+                .and_then(Result::ok)
+                .map(SchemaStartEvent::Chapter),
+            b"verse" => attribute_value(e, b"osisID")
+                .map(|id| osis_last_segment(&id).to_string())
+                .map(SchemaStartEvent::Verse),
+            _ => None,
+        }
+    }
+
+    fn classify_end(&self, e: &quick_xml::events::BytesEnd) -> Option<SchemaEndEvent> {
+        match e.name() {
+            b"div" => {
+                let depth = self.div_depth.get();
+                self.div_depth.set(depth.saturating_sub(1));
+
+                if self.book_div_depth.get() == Some(depth) {
+                    self.book_div_depth.set(None);
+                    Some(SchemaEndEvent::Book)
+                } else {
+                    None
+                }
+            }
+            b"chapter" => Some(SchemaEndEvent::Chapter),
+            b"verse" => Some(SchemaEndEvent::Verse),
+            _ => None,
+        }
+    }
+}
+
+/// Zefania: `<BIBLEBOOK bname="Genesis">`, `<CHAPTER cnumber="1">`, `<VERS vnumber="1">`.
+struct ZefaniaSchema;
+
+impl Schema for ZefaniaSchema {
+    fn classify_start(&self, e: &quick_xml::events::BytesStart) -> Option<SchemaStartEvent> {
+        match e.name() {
+            b"BIBLEBOOK" => attribute_value(e, b"bname").map(SchemaStartEvent::Book),
+            b"CHAPTER" => attribute_value(e, b"cnumber")
+                .and_then(|n| n.parse::<usize>().ok())
+                .map(SchemaStartEvent::Chapter),
+            b"VERS" => attribute_value(e, b"vnumber").map(SchemaStartEvent::Verse),
+            _ => None,
+        }
+    }
+
+    fn classify_end(&self, e: &quick_xml::events::BytesEnd) -> Option<SchemaEndEvent> {
+        match e.name() {
+            b"BIBLEBOOK" => Some(SchemaEndEvent::Book),
+            b"CHAPTER" => Some(SchemaEndEvent::Chapter),
+            b"VERS" => Some(SchemaEndEvent::Verse),
+            _ => None,
+        }
+    }
+}
+
+fn schema_for(kind: SchemaKind) -> Box<dyn Schema> {
+    match kind {
+        SchemaKind::Esv | SchemaKind::Auto => Box::new(EsvSchema),
+        SchemaKind::Osis => Box::new(OsisSchema::new()),
+        SchemaKind::Zefania => Box::new(ZefaniaSchema),
+    }
+}
+
+/// Sniffs the source's root element to pick a `Schema` when `--schema auto`
+/// (the default) is in effect.
+fn detect_schema_kind(path: &std::path::Path) -> Result<SchemaKind, Error> {
+    let mut reader = open_reader(path)?;
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => {
+                return Ok(match e.name() {
+                    b"BIBLEBOOK" | b"XMLBIBLE" => SchemaKind::Zefania,
+                    b"osis" | b"osisText" | b"div" => SchemaKind::Osis,
+                    _ => SchemaKind::Esv,
+                });
+            }
+            Ok(Event::Eof) => return Ok(SchemaKind::Esv),
+            Err(e) => return Err(Error::ParseError { source: e }),
+            _ => (),
+        }
+        buf.clear();
+    }
+}
+
+/// Returns true if `path` looks like a zip/EPUB container, either by
+/// extension or by sniffing the leading `PK\x03\x04` magic bytes.
+fn is_epub(path: &std::path::Path) -> Result<bool, Error> {
+    if path.extension().and_then(|e| e.to_str()) == Some("epub") {
+        return Ok(true);
+    }
+
+    let mut file = std::fs::File::open(path).context(IOError {})?;
+    let mut magic = [0u8; 4];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == b"PK\x03\x04"),
+        Err(_) => Ok(false),
+    }
+}
+
+fn attribute_value(e: &quick_xml::events::BytesStart, name: &[u8]) -> Option<String> {
+    e.attributes()
+        .map(|a| a.unwrap())
+        .find(|a| a.key == name)
+        .map(|a| String::from_utf8_lossy(&a.unescaped_value().unwrap()).to_string())
+}
+
+/// Finds the OPF package document's path via `META-INF/container.xml`.
+fn find_rootfile_path(container_xml: &str) -> Result<String, Error> {
+    let mut reader = Reader::from_str(container_xml);
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Empty(ref e)) | Ok(Event::Start(ref e)) if e.name() == b"rootfile" => {
+                if let Some(path) = attribute_value(e, b"full-path") {
+                    return Ok(path);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(Error::ParseError { source: e }),
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Err(Error::EpubStructure {
+        message: "container.xml has no rootfile".to_string(),
+    })
+}
+
+/// Reads an OPF package document's manifest (id -> href) and spine (the
+/// reading order, as a list of manifest ids).
+fn parse_opf(opf_xml: &str) -> (HashMap<String, String>, Vec<String>) {
+    let mut reader = Reader::from_str(opf_xml);
+    let mut buf = Vec::new();
+    let mut manifest = HashMap::new();
+    let mut spine = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Empty(ref e)) | Ok(Event::Start(ref e)) => match e.name() {
+                b"item" => {
+                    if let (Some(id), Some(href)) =
+                        (attribute_value(e, b"id"), attribute_value(e, b"href"))
+                    {
+                        manifest.insert(id, href);
+                    }
+                }
+                b"itemref" => {
+                    if let Some(idref) = attribute_value(e, b"idref") {
+                        spine.push(idref);
+                    }
+                }
+                _ => (),
+            },
+            Ok(Event::Eof) => break,
+            Err(_) => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    (manifest, spine)
+}
+
+fn read_zip_entry(
+    archive: &mut zip::ZipArchive<std::fs::File>,
+    name: &str,
+) -> Result<String, Error> {
+    let mut entry = archive.by_name(name).context(Zip {})?;
+    let mut contents = String::new();
+    entry.read_to_string(&mut contents).context(IOError {})?;
+    Ok(contents)
+}
+
+/// Walks an EPUB's spine in reading order and concatenates the XHTML content
+/// documents into a single XML stream, so the existing `<b>/<c>/<v>` event
+/// loop can run over it exactly as it would over a bare XML file.
+fn read_epub_spine(path: &std::path::Path) -> Result<String, Error> {
+    let file = std::fs::File::open(path).context(IOError {})?;
+    let mut archive = zip::ZipArchive::new(file).context(Zip {})?;
+
+    let container = read_zip_entry(&mut archive, "META-INF/container.xml")?;
+    let rootfile = find_rootfile_path(&container)?;
+
+    let opf = read_zip_entry(&mut archive, &rootfile)?;
+    let opf_dir = std::path::Path::new(&rootfile)
+        .parent()
+        .unwrap_or_else(|| std::path::Path::new(""));
+
+    let (manifest, spine) = parse_opf(&opf);
+
+    let mut combined = String::new();
+    for idref in spine {
+        let href = match manifest.get(&idref) {
+            Some(href) => href,
+            None => continue,
+        };
+        let content_path = opf_dir.join(href);
+        let content = read_zip_entry(&mut archive, &content_path.to_string_lossy())?;
+        combined.push_str(&content);
+    }
+
+    Ok(combined)
+}
+
+fn has_extension(path: &std::path::Path, ext: &str) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some(ext)
+}
+
+fn leading_bytes(path: &std::path::Path, n: usize) -> Result<Vec<u8>, Error> {
+    let mut file = std::fs::File::open(path).context(IOError {})?;
+    let mut magic = vec![0u8; n];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+fn is_gzip(path: &std::path::Path) -> Result<bool, Error> {
+    if has_extension(path, "gz") {
+        return Ok(true);
+    }
+    Ok(leading_bytes(path, 2)?.as_slice() == [0x1f, 0x8b])
+}
+
+fn is_bzip2(path: &std::path::Path) -> Result<bool, Error> {
+    if has_extension(path, "bz2") {
+        return Ok(true);
+    }
+    Ok(leading_bytes(path, 3)?.as_slice() == b"BZh")
+}
+
+/// Opens `path` as a `quick_xml::Reader`, transparently unwrapping it from
+/// its EPUB/zip container, or decompressing it, first if it looks like one.
+/// The compressed cases stay a single forward streaming pass, same as the
+/// plain-XML case, so peak memory stays flat even on gigabyte-scale inputs.
+fn open_reader(path: &std::path::Path) -> Result<Reader<Box<dyn std::io::BufRead>>, Error> {
+    if is_epub(path)? {
+        let xml = read_epub_spine(path)?;
+        return Ok(Reader::from_reader(
+            Box::new(std::io::Cursor::new(xml.into_bytes())) as Box<dyn std::io::BufRead>,
+        ));
+    }
+
+    let file = std::fs::File::open(path).context(IOError {})?;
+
+    let reader: Box<dyn std::io::BufRead> = if is_gzip(path)? {
+        Box::new(std::io::BufReader::new(flate2::read::GzDecoder::new(file)))
+    } else if is_bzip2(path)? {
+        Box::new(std::io::BufReader::new(bzip2::read::BzDecoder::new(file)))
+    } else {
+        Box::new(std::io::BufReader::new(file))
+    };
+
+    Ok(Reader::from_reader(reader))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 struct BookAndChapter {
     book: String,
     chapter: usize,
@@ -75,10 +638,161 @@ fn parse_chapter(s: String) -> Result<BookAndChapter, Error> {
     Ok(BookAndChapter { book, chapter })
 }
 
+/// Serialized sidecar contents: the full document index plus the source
+/// file's modification time and the schema it was parsed with, so a later
+/// run can tell whether it's stale.
+#[derive(Serialize, Deserialize)]
+struct CachedIndex {
+    source_mtime_secs: u64,
+    schema_name: String,
+    chapters: Vec<(BookAndChapter, Vec<ChapterItem>)>,
+}
+
+fn cache_path(source: &std::path::Path) -> std::path::PathBuf {
+    let mut cache = source.to_path_buf();
+    let cache_ext = match source.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}.bincode", ext),
+        None => "bincode".to_string(),
+    };
+    cache.set_extension(cache_ext);
+    cache
+}
+
+fn source_mtime_secs(source: &std::path::Path) -> Result<u64, Error> {
+    let metadata = std::fs::metadata(source).context(IOError {})?;
+    let modified = metadata.modified().context(IOError {})?;
+    Ok(modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs())
+}
+
+/// Parses the entire source document (every book and chapter, not just the
+/// ones `-c` selected) into a flat, bincode-serializable index. Headings are
+/// kept as `ChapterItem::Heading` entries, same as the non-cached parse.
+fn build_full_index(
+    path: &std::path::Path,
+    schema: &dyn Schema,
+) -> Result<Vec<(BookAndChapter, Vec<ChapterItem>)>, Error> {
+    let mut reader = open_reader(path)?;
+    let mut buf = Vec::new();
+
+    let mut in_book: Option<String> = None;
+    let mut in_chapter: Option<usize> = None;
+    let mut in_verse: Option<String> = None;
+    let mut in_heading = false;
+
+    let mut items = Vec::new();
+    let mut heading_text = String::new();
+    let mut chapters = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => match schema.classify_start(e) {
+                Some(SchemaStartEvent::Book(book)) if in_book.is_none() => {
+                    in_book = Some(book);
+                }
+                Some(SchemaStartEvent::Chapter(chapter_num)) if in_book.is_some() => {
+                    in_chapter = Some(chapter_num);
+                }
+                Some(SchemaStartEvent::Verse(num)) if in_book.is_some() && in_chapter.is_some() => {
+                    in_verse = Some(num);
+                }
+                Some(SchemaStartEvent::Heading) if in_book.is_some() && in_chapter.is_some() => {
+                    in_heading = true;
+                    heading_text.clear();
+                }
+                _ => (),
+            },
+            Ok(Event::End(ref e)) => match schema.classify_end(e) {
+                Some(SchemaEndEvent::Book) => {
+                    in_book = None;
+                    in_chapter = None;
+                }
+                Some(SchemaEndEvent::Chapter) => {
+                    if let (Some(book), Some(chapter)) = (&in_book, in_chapter) {
+                        let chapter_items = mem::replace(&mut items, Vec::new());
+                        chapters.push((
+                            BookAndChapter {
+                                book: book.to_string(),
+                                chapter,
+                            },
+                            chapter_items,
+                        ));
+                    }
+                    in_chapter = None;
+                }
+                Some(SchemaEndEvent::Verse) if in_book.is_some() && in_chapter.is_some() => {
+                    in_verse = None;
+                }
+                Some(SchemaEndEvent::Heading) if in_heading => {
+                    in_heading = false;
+                    items.push(ChapterItem::Heading(mem::replace(
+                        &mut heading_text,
+                        String::new(),
+                    )));
+                }
+                _ => (),
+            },
+            Ok(Event::Text(ref t)) => {
+                if in_heading {
+                    let value = t.unescape_and_decode(&reader).unwrap();
+                    heading_text.push_str(&value);
+                } else if let (Some(_), Some(_), Some(verse)) = (&in_book, in_chapter, &in_verse) {
+                    let value = t.unescape_and_decode(&reader).unwrap();
+                    items.push(ChapterItem::Verse(format!("{}. {}", verse, value)));
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(Error::ParseError { source: e }),
+            _ => (),
+        }
+    }
+
+    Ok(chapters)
+}
+
+/// Loads the on-disk index cache if it's still fresh with respect to
+/// `path`'s modification time and the active schema, otherwise rebuilds it
+/// and writes it back.
+fn load_or_build_index(
+    path: &std::path::Path,
+    schema_kind: &SchemaKind,
+    schema: &dyn Schema,
+) -> Result<Vec<(BookAndChapter, Vec<ChapterItem>)>, Error> {
+    let cache_path = cache_path(path);
+    let mtime_secs = source_mtime_secs(path)?;
+    let schema_name = format!("{:?}", schema_kind);
+
+    if let Ok(cache_file) = std::fs::File::open(&cache_path) {
+        if let Ok(cached) = bincode::deserialize_from::<_, CachedIndex>(cache_file) {
+            if cached.source_mtime_secs == mtime_secs && cached.schema_name == schema_name {
+                return Ok(cached.chapters);
+            }
+        }
+    }
+
+    let chapters = build_full_index(path, schema)?;
+
+    let cache_file = std::fs::File::create(&cache_path).context(IOError {})?;
+    let cached = CachedIndex {
+        source_mtime_secs: mtime_secs,
+        schema_name,
+        chapters,
+    };
+    bincode::serialize_into(cache_file, &cached).context(Cache {})?;
+
+    Ok(cached.chapters)
+}
+
 fn main() -> Result<(), Error> {
     let config = Config::from_args();
-    let mut reader = Reader::from_file(config.path).context(OpenXML {})?;
-    let mut buf = Vec::new();
+    let heading_depth = config.heading_depth;
+    let schema_kind = match config.schema {
+        SchemaKind::Auto => detect_schema_kind(&config.path)?,
+        other => other,
+    };
+    let schema = schema_for(schema_kind);
 
     let expected_chapters = config
         .chapters
@@ -96,110 +810,773 @@ fn main() -> Result<(), Error> {
                 acc
             });
 
-    let mut in_book: Option<(String, &[usize])> = None;
-    let mut in_chapter: Option<usize> = None;
-    let mut in_verse: Option<String> = None;
+    let mut finished_chapters: Vec<(BookAndChapter, Vec<ChapterItem>)> = Vec::new();
+
+    if config.use_cache {
+        let index = load_or_build_index(&config.path, &schema_kind, schema.as_ref())?;
+        for (bc, items) in index {
+            let matches = all_expected_books
+                .get(&bc.book)
+                .map(|chapters| chapters.iter().any(|c| *c == bc.chapter))
+                .unwrap_or(false);
+            if matches {
+                finished_chapters.push((bc, items));
+            }
+        }
+    } else {
+        let mut reader = open_reader(&config.path)?;
+        let mut buf = Vec::new();
 
-    let mut verses = Vec::new();
-    let mut finished_chapters: Vec<(BookAndChapter, Vec<String>)> = Vec::new();
+        let mut in_book: Option<(String, &[usize])> = None;
+        let mut in_chapter: Option<usize> = None;
+        let mut in_verse: Option<String> = None;
+        let mut in_heading = false;
 
-    loop {
-        match reader.read_event(&mut buf) {
-            Ok(Event::Start(ref e)) => {
-                match (e.name(), &in_book, in_chapter) {
-                    (b"b", &None, _) => {
-                        // Book
-                        let book = get_name(e);
+        let mut items = Vec::new();
+        let mut heading_text = String::new();
+
+        loop {
+            match reader.read_event(&mut buf) {
+                Ok(Event::Start(ref e)) => match schema.classify_start(e) {
+                    Some(SchemaStartEvent::Book(book)) if in_book.is_none() => {
                         in_book = all_expected_books
                             .get(&book)
                             .map(|chapters| (book.to_string(), chapters.as_slice()));
                     }
-                    (b"c", Some((_, chapters)), _) => {
-                        let chapter_num = get_name(e).parse::<usize>().unwrap();
-                        if chapters.iter().any(|c| *c == chapter_num) {
-                            in_chapter = Some(chapter_num);
-                        } else {
+                    Some(SchemaStartEvent::Chapter(chapter_num)) => {
+                        if let Some((_, chapters)) = &in_book {
+                            if chapters.iter().any(|c| *c == chapter_num) {
+                                in_chapter = Some(chapter_num);
+                            } else {
+                                in_chapter = None;
+                            }
+                        }
+                    }
+                    Some(SchemaStartEvent::Verse(num))
+                        if in_book.is_some() && in_chapter.is_some() =>
+                    {
+                        in_verse = Some(num);
+                    }
+                    Some(SchemaStartEvent::Heading)
+                        if in_book.is_some() && in_chapter.is_some() =>
+                    {
+                        in_heading = true;
+                        heading_text.clear();
+                    }
+                    _ => (),
+                },
+                Ok(Event::End(ref e)) => match schema.classify_end(e) {
+                    Some(SchemaEndEvent::Book) => {
+                        in_book = None;
+                        in_chapter = None;
+                    }
+                    Some(SchemaEndEvent::Chapter) => {
+                        if let (Some((book, _)), Some(chapter)) = (&in_book, in_chapter) {
+                            // Finished the chapter we're looking for.
+                            let chapter_items = mem::replace(&mut items, Vec::new());
+                            finished_chapters.push((
+                                BookAndChapter {
+                                    book: book.to_string(),
+                                    chapter,
+                                },
+                                chapter_items,
+                            ));
+
                             in_chapter = None;
+
+                            if finished_chapters.len() == num_expected_chapters {
+                                // All done!
+                                break;
+                            }
                         }
                     }
-                    (b"v", Some(_), Some(_)) => {
-                        in_verse = Some(get_name(e).to_string());
+                    Some(SchemaEndEvent::Verse) if in_book.is_some() && in_chapter.is_some() => {
+                        in_verse = None;
+                    }
+                    Some(SchemaEndEvent::Heading) if in_heading => {
+                        in_heading = false;
+                        items.push(ChapterItem::Heading(mem::replace(
+                            &mut heading_text,
+                            String::new(),
+                        )));
                     }
                     _ => (),
+                },
+                Ok(Event::Text(ref t)) => {
+                    if in_heading {
+                        let value = t.unescape_and_decode(&reader).unwrap();
+                        heading_text.push_str(&value);
+                    } else if let (Some(_), Some(_), Some(verse)) =
+                        (&in_book, in_chapter, &in_verse)
+                    {
+                        let value = t.unescape_and_decode(&reader).unwrap();
+                        items.push(ChapterItem::Verse(format!("{}. {}", verse, value)));
+                    }
                 }
+                Ok(Event::Eof) => break,
+                Err(e) => return Err(Error::ParseError { source: e }),
+                _ => (),
             }
-            Ok(Event::End(ref e)) => match (e.name(), &in_book, in_chapter) {
-                (b"b", _, _) => {
-                    in_book = None;
-                    in_chapter = None;
-                }
-                (b"c", Some((book, _)), Some(chapter)) => {
-                    // Finished the chapter we're looking for.
-                    let chapter_verses = mem::replace(&mut verses, Vec::new());
-                    finished_chapters.push((
-                        BookAndChapter {
-                            book: book.to_string(),
-                            chapter,
-                        },
-                        chapter_verses,
-                    ));
+        }
+    }
 
-                    in_chapter = None;
+    match config.format {
+        OutputFormat::Roam => {
+            let docs = finished_chapters
+                .into_iter()
+                .map(|(bc, items)| {
+                    let verse_blocks = nest_under_headings(items, heading_depth);
 
-                    if finished_chapters.len() == num_expected_chapters {
-                        // All done!
-                        break;
+                    RoamDocument {
+                        title: format!("{} {}", bc.book, bc.chapter),
+                        children: vec![
+                            RoamBlock {
+                                string: format!("Bible Book:: [[{}]]", bc.book),
+                                heading: None,
+                                children: None,
+                            },
+                            RoamBlock {
+                                string: format!("[[{} {}]]", bc.book, bc.chapter),
+                                heading: None,
+                                children: Some(verse_blocks),
+                            },
+                        ],
                     }
+                })
+                .collect::<Vec<_>>();
+
+            serde_json::to_writer(std::io::stdout(), &docs).context(WriteJSON)?;
+        }
+        OutputFormat::Mdbook => write_mdbook(finished_chapters, heading_depth)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn unique_temp_dir(label: &str) -> std::path::PathBuf {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("xbr_test_dir_{}_{}_{}", std::process::id(), label, n))
+    }
+
+    /// Runs `f` with the process cwd set to a fresh temp directory, since
+    /// `write_mdbook` always writes relative to `src/`. Restores the
+    /// original cwd afterwards.
+    fn with_temp_cwd<R>(label: &str, f: impl FnOnce() -> R) -> R {
+        let original = std::env::current_dir().unwrap();
+        let dir = unique_temp_dir(label);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+
+        let result = f();
+
+        std::env::set_current_dir(&original).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        result
+    }
+
+    #[test]
+    fn sanitize_book_name_rejects_path_traversal_and_separators() {
+        assert!(sanitize_book_name("Genesis").is_ok());
+        assert!(sanitize_book_name("").is_err());
+        assert!(sanitize_book_name(".").is_err());
+        assert!(sanitize_book_name("..").is_err());
+        assert!(sanitize_book_name("a/b").is_err());
+        assert!(sanitize_book_name("../../../../tmp/xbr_pwn_marker").is_err());
+    }
+
+    #[test]
+    fn group_chapters_by_book_preserves_first_seen_order_and_sorts_chapters() {
+        let chapters = vec![
+            (
+                BookAndChapter {
+                    book: "Exodus".to_string(),
+                    chapter: 2,
+                },
+                vec![ChapterItem::Verse("a".to_string())],
+            ),
+            (
+                BookAndChapter {
+                    book: "Genesis".to_string(),
+                    chapter: 3,
+                },
+                vec![ChapterItem::Verse("b".to_string())],
+            ),
+            (
+                BookAndChapter {
+                    book: "Genesis".to_string(),
+                    chapter: 1,
+                },
+                vec![ChapterItem::Verse("c".to_string())],
+            ),
+        ];
+
+        let grouped = group_chapters_by_book(chapters);
+
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0].0, "Exodus");
+        assert_eq!(grouped[1].0, "Genesis");
+        let genesis_chapter_nums: Vec<usize> = grouped[1].1.iter().map(|(n, _)| *n).collect();
+        assert_eq!(genesis_chapter_nums, vec![1, 3]);
+    }
+
+    /// Reproduces the review repro: a book name smuggling `..` components
+    /// must not let `write_mdbook` escape the `src/` tree.
+    #[test]
+    fn write_mdbook_rejects_path_traversal_in_book_name() {
+        with_temp_cwd("mdbook_escape", || {
+            let chapters = vec![(
+                BookAndChapter {
+                    book: "../../../../tmp/xbr_pwn_marker".to_string(),
+                    chapter: 1,
+                },
+                vec![ChapterItem::Verse("1. pwned".to_string())],
+            )];
+
+            assert!(write_mdbook(chapters, 2).is_err());
+        });
+    }
+
+    #[test]
+    fn write_mdbook_writes_expected_tree_for_safe_book_names() {
+        with_temp_cwd("mdbook_tree", || {
+            let chapters = vec![(
+                BookAndChapter {
+                    book: "Genesis".to_string(),
+                    chapter: 1,
+                },
+                vec![
+                    ChapterItem::Heading("In The Beginning".to_string()),
+                    ChapterItem::Verse("1. text".to_string()),
+                ],
+            )];
+
+            write_mdbook(chapters, 2).unwrap();
+
+            let summary = std::fs::read_to_string("src/SUMMARY.md").unwrap();
+            assert!(summary.contains("[Genesis]()"));
+            assert!(summary.contains("[Genesis 1](./Genesis/1.md)"));
+
+            let chapter_md = std::fs::read_to_string("src/Genesis/1.md").unwrap();
+            assert!(chapter_md.contains("# Genesis 1"));
+            assert!(chapter_md.contains("## In The Beginning"));
+            assert!(chapter_md.contains("1. text"));
+        });
+    }
+
+    #[test]
+    fn find_rootfile_path_locates_opf_from_container_xml() {
+        let container = r#"<?xml version="1.0"?>
+<container>
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+        assert_eq!(find_rootfile_path(container).unwrap(), "OEBPS/content.opf");
+    }
+
+    #[test]
+    fn find_rootfile_path_errors_without_a_rootfile() {
+        assert!(find_rootfile_path("<container><rootfiles></rootfiles></container>").is_err());
+    }
+
+    #[test]
+    fn parse_opf_builds_manifest_and_reading_order_spine() {
+        let opf = r#"<package>
+  <manifest>
+    <item id="c1" href="chapter1.xhtml"/>
+    <item id="c2" href="chapter2.xhtml"/>
+  </manifest>
+  <spine>
+    <itemref idref="c2"/>
+    <itemref idref="c1"/>
+  </spine>
+</package>"#;
+        let (manifest, spine) = parse_opf(opf);
+
+        assert_eq!(
+            manifest.get("c1").map(String::as_str),
+            Some("chapter1.xhtml")
+        );
+        assert_eq!(
+            manifest.get("c2").map(String::as_str),
+            Some("chapter2.xhtml")
+        );
+        assert_eq!(spine, vec!["c2".to_string(), "c1".to_string()]);
+    }
+
+    /// Builds a minimal real EPUB zip (container.xml -> OPF -> two spine
+    /// content docs) and checks `read_epub_spine` concatenates them in
+    /// spine order, not manifest/declaration order.
+    #[test]
+    fn read_epub_spine_concatenates_content_docs_in_spine_order() {
+        let path = unique_temp_path("epub").with_extension("epub");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut zip = zip::ZipWriter::new(file);
+        let options = zip::write::FileOptions::default();
+
+        zip.start_file("META-INF/container.xml", options).unwrap();
+        zip.write_all(
+            br#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#,
+        )
+        .unwrap();
+
+        zip.start_file("OEBPS/content.opf", options).unwrap();
+        zip.write_all(
+            br#"<package>
+                <manifest>
+                    <item id="c1" href="chapter1.xhtml"/>
+                    <item id="c2" href="chapter2.xhtml"/>
+                </manifest>
+                <spine>
+                    <itemref idref="c2"/>
+                    <itemref idref="c1"/>
+                </spine>
+            </package>"#,
+        )
+        .unwrap();
+
+        zip.start_file("OEBPS/chapter1.xhtml", options).unwrap();
+        zip.write_all(b"<b n=\"Genesis\">FIRST</b>").unwrap();
+
+        zip.start_file("OEBPS/chapter2.xhtml", options).unwrap();
+        zip.write_all(b"<b n=\"Genesis\">SECOND</b>").unwrap();
+
+        zip.finish().unwrap();
+
+        let combined = read_epub_spine(&path).unwrap();
+        let second_pos = combined.find("SECOND").unwrap();
+        let first_pos = combined.find("FIRST").unwrap();
+        assert!(
+            second_pos < first_pos,
+            "spine order (c2 then c1) should put SECOND before FIRST, got: {}",
+            combined
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn is_epub_detects_by_extension_and_by_zip_magic_bytes() {
+        let named = unique_temp_path("epub_ext").with_extension("epub");
+        std::fs::write(&named, b"not actually a zip").unwrap();
+        assert!(is_epub(&named).unwrap());
+        let _ = std::fs::remove_file(&named);
+
+        let unnamed = unique_temp_path("epub_magic");
+        std::fs::write(&unnamed, b"PK\x03\x04rest of a real zip").unwrap();
+        assert!(is_epub(&unnamed).unwrap());
+        let _ = std::fs::remove_file(&unnamed);
+
+        let plain = unique_temp_path("epub_plain");
+        std::fs::write(&plain, b"<b n=\"Genesis\"></b>").unwrap();
+        assert!(!is_epub(&plain).unwrap());
+        let _ = std::fs::remove_file(&plain);
+    }
+
+    #[test]
+    fn is_gzip_detects_by_extension_and_by_magic_bytes() {
+        let named = unique_temp_path("gz_ext").with_extension("gz");
+        std::fs::write(&named, b"whatever").unwrap();
+        assert!(is_gzip(&named).unwrap());
+        let _ = std::fs::remove_file(&named);
+
+        let unnamed = unique_temp_path("gz_magic");
+        std::fs::write(&unnamed, [0x1f, 0x8b, 0x08, 0x00]).unwrap();
+        assert!(is_gzip(&unnamed).unwrap());
+        let _ = std::fs::remove_file(&unnamed);
+
+        let plain = unique_temp_path("gz_plain");
+        std::fs::write(&plain, b"<b n=\"Genesis\"></b>").unwrap();
+        assert!(!is_gzip(&plain).unwrap());
+        let _ = std::fs::remove_file(&plain);
+    }
+
+    #[test]
+    fn is_bzip2_detects_by_extension_and_by_magic_bytes() {
+        let named = unique_temp_path("bz2_ext").with_extension("bz2");
+        std::fs::write(&named, b"whatever").unwrap();
+        assert!(is_bzip2(&named).unwrap());
+        let _ = std::fs::remove_file(&named);
+
+        let unnamed = unique_temp_path("bz2_magic");
+        std::fs::write(&unnamed, b"BZh91AY").unwrap();
+        assert!(is_bzip2(&unnamed).unwrap());
+        let _ = std::fs::remove_file(&unnamed);
+
+        let plain = unique_temp_path("bz2_plain");
+        std::fs::write(&plain, b"<b n=\"Genesis\"></b>").unwrap();
+        assert!(!is_bzip2(&plain).unwrap());
+        let _ = std::fs::remove_file(&plain);
+    }
+
+    /// Round-trips a gzip-compressed source through `open_reader` to check
+    /// the decompression branch actually yields readable XML events, not
+    /// just that sniffing returns `true`.
+    #[test]
+    fn open_reader_transparently_decompresses_gzip() {
+        let path = unique_temp_path("gz_roundtrip").with_extension("gz");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        encoder
+            .write_all(br#"<b n="Genesis"><c n="1"><v n="1">In the beginning</v></c></b>"#)
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let mut reader = open_reader(&path).unwrap();
+        let mut buf = Vec::new();
+        let mut saw_verse_text = false;
+        loop {
+            match reader.read_event(&mut buf) {
+                Ok(Event::Text(ref t))
+                    if t.unescape_and_decode(&reader).unwrap().contains("In the beginning") =>
+                {
+                    saw_verse_text = true;
                 }
-                (b"v", Some(_), Some(_)) => {
-                    in_verse = None;
-                }
+                Ok(Event::Eof) => break,
+                Err(e) => panic!("xml error: {:?}", e),
                 _ => (),
-            },
-            Ok(Event::Text(ref t)) => match (&in_book, in_chapter, &in_verse) {
-                (Some(_), Some(_), Some(verse)) => {
-                    let value = t.unescape_and_decode(&reader).unwrap();
-                    verses.push(format!("{}. {}", verse, value));
+            }
+            buf.clear();
+        }
+        assert!(saw_verse_text);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Same round-trip, but for the bzip2 decompression branch.
+    #[test]
+    fn open_reader_transparently_decompresses_bzip2() {
+        let path = unique_temp_path("bz2_roundtrip").with_extension("bz2");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut encoder = bzip2::write::BzEncoder::new(file, bzip2::Compression::default());
+        encoder
+            .write_all(br#"<b n="Genesis"><c n="1"><v n="1">In the beginning</v></c></b>"#)
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let mut reader = open_reader(&path).unwrap();
+        let mut buf = Vec::new();
+        let mut saw_verse_text = false;
+        loop {
+            match reader.read_event(&mut buf) {
+                Ok(Event::Text(ref t))
+                    if t.unescape_and_decode(&reader).unwrap().contains("In the beginning") =>
+                {
+                    saw_verse_text = true;
                 }
-                _ => {}
-            },
-            Ok(Event::Eof) => break,
-            Err(e) => return Err(Error::ParseError { source: e }),
-            _ => (),
+                Ok(Event::Eof) => break,
+                Err(e) => panic!("xml error: {:?}", e),
+                _ => (),
+            }
+            buf.clear();
         }
+        assert!(saw_verse_text);
+
+        let _ = std::fs::remove_file(&path);
     }
 
-    let docs = finished_chapters
-        .into_iter()
-        .map(|(bc, verses)| {
-            let verse_blocks = verses
-                .into_iter()
-                .map(|v| RoamBlock {
-                    string: v,
+    fn collect_events(
+        xml: &str,
+        schema: &dyn Schema,
+    ) -> (Vec<Option<SchemaStartEvent>>, Vec<Option<SchemaEndEvent>>) {
+        let mut reader = Reader::from_str(xml);
+        let mut buf = Vec::new();
+        let mut starts = Vec::new();
+        let mut ends = Vec::new();
+
+        loop {
+            match reader.read_event(&mut buf) {
+                Ok(Event::Start(ref e)) => starts.push(schema.classify_start(e)),
+                Ok(Event::End(ref e)) => ends.push(schema.classify_end(e)),
+                Ok(Event::Eof) => break,
+                Err(e) => panic!("xml error in test fixture: {:?}", e),
+                _ => (),
+            }
+            buf.clear();
+        }
+
+        (starts, ends)
+    }
+
+    #[test]
+    fn osis_last_segment_returns_final_dot_segment() {
+        assert_eq!(osis_last_segment("Gen.1.1"), "1");
+        assert_eq!(osis_last_segment("Gen"), "Gen");
+    }
+
+    #[test]
+    fn esv_schema_classifies_book_chapter_verse_title() {
+        let xml = r#"<b n="Genesis"><c n="1"><title>In the beginning</title><v n="1">text</v></c></b>"#;
+        let (starts, ends) = collect_events(xml, &EsvSchema);
+
+        assert_eq!(
+            starts,
+            vec![
+                Some(SchemaStartEvent::Book("Genesis".to_string())),
+                Some(SchemaStartEvent::Chapter(1)),
+                Some(SchemaStartEvent::Heading),
+                Some(SchemaStartEvent::Verse("1".to_string())),
+            ]
+        );
+        assert_eq!(
+            ends,
+            vec![
+                Some(SchemaEndEvent::Heading),
+                Some(SchemaEndEvent::Verse),
+                Some(SchemaEndEvent::Chapter),
+                Some(SchemaEndEvent::Book),
+            ]
+        );
+    }
+
+    #[test]
+    fn osis_schema_classifies_book_chapter_verse() {
+        let xml = r#"<div type="book" osisID="Gen"><chapter osisID="Gen.1"><verse osisID="Gen.1.1">text</verse></chapter></div>"#;
+        let (starts, ends) = collect_events(xml, &OsisSchema::new());
+
+        assert_eq!(
+            starts,
+            vec![
+                Some(SchemaStartEvent::Book("Gen".to_string())),
+                Some(SchemaStartEvent::Chapter(1)),
+                Some(SchemaStartEvent::Verse("1".to_string())),
+            ]
+        );
+        assert_eq!(
+            ends,
+            vec![
+                Some(SchemaEndEvent::Verse),
+                Some(SchemaEndEvent::Chapter),
+                Some(SchemaEndEvent::Book),
+            ]
+        );
+    }
+
+    /// A non-book `<div type="section">` nested inside the book div must not
+    /// be mistaken for the book's own close.
+    #[test]
+    fn osis_schema_ignores_nested_non_book_divs() {
+        let xml = r#"<div type="book" osisID="Gen"><chapter osisID="Gen.1">
+            <div type="section"><verse osisID="Gen.1.1">In the beginning</verse></div>
+            <verse osisID="Gen.1.2">And the earth</verse>
+        </chapter></div>"#;
+        let (starts, ends) = collect_events(xml, &OsisSchema::new());
+
+        assert_eq!(
+            starts,
+            vec![
+                Some(SchemaStartEvent::Book("Gen".to_string())),
+                Some(SchemaStartEvent::Chapter(1)),
+                None, // <div type="section">
+                Some(SchemaStartEvent::Verse("1".to_string())),
+                Some(SchemaStartEvent::Verse("2".to_string())),
+            ]
+        );
+        assert_eq!(
+            ends,
+            vec![
+                Some(SchemaEndEvent::Verse),
+                None, // </div> closing the section, not the book
+                Some(SchemaEndEvent::Verse),
+                Some(SchemaEndEvent::Chapter),
+                Some(SchemaEndEvent::Book),
+            ]
+        );
+    }
+
+    #[test]
+    fn zefania_schema_classifies_book_chapter_verse() {
+        let xml = r#"<BIBLEBOOK bname="Genesis"><CHAPTER cnumber="1"><VERS vnumber="1">text</VERS></CHAPTER></BIBLEBOOK>"#;
+        let (starts, ends) = collect_events(xml, &ZefaniaSchema);
+
+        assert_eq!(
+            starts,
+            vec![
+                Some(SchemaStartEvent::Book("Genesis".to_string())),
+                Some(SchemaStartEvent::Chapter(1)),
+                Some(SchemaStartEvent::Verse("1".to_string())),
+            ]
+        );
+        assert_eq!(
+            ends,
+            vec![
+                Some(SchemaEndEvent::Verse),
+                Some(SchemaEndEvent::Chapter),
+                Some(SchemaEndEvent::Book),
+            ]
+        );
+    }
+
+    #[test]
+    fn nest_under_headings_groups_verses_under_preceding_heading() {
+        let items = vec![
+            ChapterItem::Verse("1. before any heading".to_string()),
+            ChapterItem::Heading("Section A".to_string()),
+            ChapterItem::Verse("2. under section a".to_string()),
+            ChapterItem::Verse("3. also under section a".to_string()),
+            ChapterItem::Heading("Section B".to_string()),
+            ChapterItem::Verse("4. under section b".to_string()),
+        ];
+
+        let blocks = nest_under_headings(items, 3);
+
+        assert_eq!(
+            blocks,
+            vec![
+                RoamBlock {
+                    string: "1. before any heading".to_string(),
                     heading: None,
                     children: None,
-                })
-                .collect::<Vec<_>>();
-
-            RoamDocument {
-                title: format!("{} {}", bc.book, bc.chapter),
-                children: vec![
-                    RoamBlock {
-                        string: format!("Bible Book:: [[{}]]", bc.book),
+                },
+                RoamBlock {
+                    string: "Section A".to_string(),
+                    heading: Some(3),
+                    children: Some(vec![
+                        RoamBlock {
+                            string: "2. under section a".to_string(),
+                            heading: None,
+                            children: None,
+                        },
+                        RoamBlock {
+                            string: "3. also under section a".to_string(),
+                            heading: None,
+                            children: None,
+                        },
+                    ]),
+                },
+                RoamBlock {
+                    string: "Section B".to_string(),
+                    heading: Some(3),
+                    children: Some(vec![RoamBlock {
+                        string: "4. under section b".to_string(),
                         heading: None,
                         children: None,
-                    },
-                    RoamBlock {
-                        string: format!("[[{} {}]]", bc.book, bc.chapter),
-                        heading: None,
-                        children: Some(verse_blocks),
-                    },
-                ],
-            }
-        })
-        .collect::<Vec<_>>();
+                    }]),
+                },
+            ]
+        );
+    }
+
+    fn unique_temp_path(label: &str) -> std::path::PathBuf {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("xbr_test_{}_{}_{}.xml", std::process::id(), label, n))
+    }
 
-    serde_json::to_writer(std::io::stdout(), &docs).context(WriteJSON)?;
+    #[test]
+    fn load_or_build_index_preserves_headings_through_cache_round_trip() {
+        let path = unique_temp_path("headings");
+        std::fs::write(
+            &path,
+            r#"<b n="Genesis"><c n="1"><title>Section Heading</title><v n="1">In the beginning</v></c></b>"#,
+        )
+        .unwrap();
+        let cache = cache_path(&path);
 
-    Ok(())
+        let chapters = load_or_build_index(&path, &SchemaKind::Esv, &EsvSchema).unwrap();
+        let expected = vec![(
+            BookAndChapter {
+                book: "Genesis".to_string(),
+                chapter: 1,
+            },
+            vec![
+                ChapterItem::Heading("Section Heading".to_string()),
+                ChapterItem::Verse("1. In the beginning".to_string()),
+            ],
+        )];
+        assert_eq!(chapters, expected);
+
+        // Same path, mtime and schema: should come back from the cache file
+        // just written, with the heading still intact.
+        let cached_chapters = load_or_build_index(&path, &SchemaKind::Esv, &EsvSchema).unwrap();
+        assert_eq!(cached_chapters, expected);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&cache);
+    }
+
+    #[test]
+    fn load_or_build_index_ignores_stale_cache_with_mismatched_schema() {
+        let path = unique_temp_path("schema_stale");
+        std::fs::write(&path, r#"<b n="Exodus"><c n="2"><v n="5">text</v></c></b>"#).unwrap();
+        let cache = cache_path(&path);
+
+        // A cache file that's fresh by mtime but was built under a different
+        // schema, holding an obviously-wrong sentinel chapter so a stale read
+        // is easy to tell apart from a correct, freshly-parsed one.
+        let stale = CachedIndex {
+            source_mtime_secs: source_mtime_secs(&path).unwrap(),
+            schema_name: format!("{:?}", SchemaKind::Osis),
+            chapters: vec![(
+                BookAndChapter {
+                    book: "STALE".to_string(),
+                    chapter: 999,
+                },
+                vec![ChapterItem::Verse("stale".to_string())],
+            )],
+        };
+        let cache_file = std::fs::File::create(&cache).unwrap();
+        bincode::serialize_into(cache_file, &stale).unwrap();
+
+        let chapters = load_or_build_index(&path, &SchemaKind::Esv, &EsvSchema).unwrap();
+
+        assert_eq!(
+            chapters,
+            vec![(
+                BookAndChapter {
+                    book: "Exodus".to_string(),
+                    chapter: 2,
+                },
+                vec![ChapterItem::Verse("5. text".to_string())],
+            )]
+        );
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&cache);
+    }
+
+    #[test]
+    fn load_or_build_index_ignores_stale_cache_with_mismatched_mtime() {
+        let path = unique_temp_path("mtime_stale");
+        std::fs::write(&path, r#"<b n="Leviticus"><c n="3"><v n="1">text</v></c></b>"#).unwrap();
+        let cache = cache_path(&path);
+
+        let stale = CachedIndex {
+            source_mtime_secs: 1, // not this file's real mtime
+            schema_name: format!("{:?}", SchemaKind::Esv),
+            chapters: vec![(
+                BookAndChapter {
+                    book: "STALE".to_string(),
+                    chapter: 999,
+                },
+                vec![ChapterItem::Verse("stale".to_string())],
+            )],
+        };
+        let cache_file = std::fs::File::create(&cache).unwrap();
+        bincode::serialize_into(cache_file, &stale).unwrap();
+
+        let chapters = load_or_build_index(&path, &SchemaKind::Esv, &EsvSchema).unwrap();
+
+        assert_eq!(
+            chapters,
+            vec![(
+                BookAndChapter {
+                    book: "Leviticus".to_string(),
+                    chapter: 3,
+                },
+                vec![ChapterItem::Verse("1. text".to_string())],
+            )]
+        );
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&cache);
+    }
 }